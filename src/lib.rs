@@ -11,26 +11,43 @@ extern crate jsonrpc;
 extern crate serde;
 extern crate strason;
 
+extern crate base64;
+extern crate futures;
+extern crate hyper;
+
 extern crate bitcoin;
 extern crate bitcoin_rpc_json;
 
 use std::fmt::{self, Display, Formatter};
-
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use hyper::{Body, Client as HyperClient};
 use jsonrpc::client::Client;
 use strason::Json;
 
+use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::network::serialize as bitcoin_ser;
-use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::hash::{hex_bytes, HexError, Sha256dHash};
 
 use bitcoin_rpc_json::*;
 
 macro_rules! rpc_request {
-    ($client:expr, $name:expr, $params:expr) => {
+    ($self:expr, $name:expr, $params:expr) => {
         {
-            let request = $client.build_request($name, $params);
-            $client.send_request(&request)
-                .map_err(|e| $crate::Error::new(e.into(), "RPC error"))?
+            let request = $self.client.build_request($name, $params);
+            let response = $self.send_with_retry(&request)?;
+            // bitcoind signals failures through the JSON-RPC `error` object;
+            // surface its code and message so callers can branch on them.
+            if let Some(ref error) = response.error {
+                return Err($crate::rpc_error(error));
+            }
+            response
         }
     }
 }
@@ -42,7 +59,7 @@ macro_rules! rpc_method {
     ) => {
         $(#[$outer:meta])*
         pub fn $rpc_method(&self) -> $crate::RpcResult<$ty> {
-            let response = rpc_request!(&self.client,
+            let response = rpc_request!(self,
                                         stringify!($rpc_method).to_string(),
                                         vec![]);
 
@@ -56,9 +73,32 @@ macro_rules! rpc_method {
 
 pub type RpcResult<T> = Result<T, Error>;
 
+/// The largest backoff exponent we apply; beyond this the doublings are
+/// clamped so the multiplication can't overflow.
+const MAX_BACKOFF_EXPONENT: u32 = 16;
+
+/// The ceiling on a single backoff wait; the exponential growth is clamped to
+/// this so a caller with a large `max_retries` never blocks for longer.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Controls how transport failures are retried.
+///
+/// Only transport-level errors (a dropped connection, a timeout) are retried;
+/// errors returned by the daemon itself fail immediately.
+#[derive(Clone, Debug)]
+pub struct RetryConfig {
+    /// The maximum number of times a failed request is retried.
+    pub max_retries: u32,
+    /// The base backoff, doubled after every attempt for at most
+    /// `MAX_BACKOFF_EXPONENT` doublings; a single wait never exceeds
+    /// `MAX_BACKOFF`.
+    pub backoff: Duration,
+}
+
 /// A Handle to a Bitcoin JSON-RPC connection
 pub struct BitcoinRpc {
     client: Client,
+    retry: Option<RetryConfig>,
 }
 
 impl BitcoinRpc {
@@ -68,7 +108,72 @@ impl BitcoinRpc {
         // around is ok.
         debug_assert!(pass.is_none() || user.is_some());
 
-        BitcoinRpc { client: Client::new(url, user, pass) }
+        BitcoinRpc { client: Client::new(url, user, pass), retry: None }
+    }
+
+    /// Creates a client that transparently retries transport failures.
+    ///
+    /// Transport-level errors (connection reset, timeout) are retried with
+    /// exponential backoff up to `config.max_retries`; daemon/RPC errors are
+    /// returned immediately. This is intended for long-running processes
+    /// talking to a trusted local node.
+    pub fn with_retry(
+        url: String,
+        user: Option<String>,
+        pass: Option<String>,
+        config: RetryConfig,
+    ) -> Self {
+        debug_assert!(pass.is_none() || user.is_some());
+
+        BitcoinRpc { client: Client::new(url, user, pass), retry: Some(config) }
+    }
+
+    /// Creates a client authenticating with the contents of a bitcoind
+    /// `.cookie` file.
+    ///
+    /// bitcoind writes a `__cookie__:<random>` pair to the `.cookie` file in
+    /// its datadir on every startup; reading it is the preferred way to talk
+    /// to a local node without configuring static rpcuser credentials.
+    pub fn from_cookie_file(url: String, path: PathBuf) -> RpcResult<Self> {
+        let contents = fs::read_to_string(&path)?;
+        let (user, pass) = parse_cookie(&contents)?;
+
+        Ok(BitcoinRpc { client: Client::new(url, Some(user), Some(pass)), retry: None })
+    }
+
+    /// Sends a request, retrying transport failures according to the
+    /// configured [`RetryConfig`], if any.
+    ///
+    /// [`RetryConfig`]: struct.RetryConfig.html
+    fn send_with_retry(&self, request: &jsonrpc::Request) -> RpcResult<jsonrpc::Response> {
+        let mut attempt = 0;
+        loop {
+            match self.client.send_request(request) {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    let retry = match self.retry {
+                        Some(ref config) if attempt < config.max_retries
+                            && is_transport_error(&e) => true,
+                        _ => false,
+                    };
+                    if !retry {
+                        return Err(Error::new(e.into(), "RPC error"));
+                    }
+                    // Back off exponentially before the next attempt. Cap the
+                    // exponent and multiply with `checked_mul` so the retry
+                    // path can never itself overflow and panic, however large
+                    // `max_retries` is.
+                    let config = self.retry.as_ref().unwrap();
+                    let factor = 2u32.saturating_pow(attempt.min(MAX_BACKOFF_EXPONENT));
+                    let backoff = config.backoff
+                        .checked_mul(factor)
+                        .map(|backoff| backoff.min(MAX_BACKOFF))
+                        .unwrap_or(MAX_BACKOFF);
+                    thread::sleep(backoff);
+                    attempt += 1;
+                },
+            }
+        }
     }
 
     // mining
@@ -82,12 +187,12 @@ impl BitcoinRpc {
           Into<Option<mining::EstimateMode>>
     {
         let mut params = Vec::new();
-        params.push(Json::from_serialize(conf_target).unwrap());
+        params.push(Json::from_serialize(conf_target)?);
         if let Some(estimate_mode) = estimate_mode.into() {
-            params.push(Json::from_serialize(estimate_mode).unwrap())
+            params.push(Json::from_serialize(estimate_mode)?)
         }
 
-        let response = rpc_request!(&self.client,
+        let response = rpc_request!(self,
                                     "estimatesmartfee".to_string(),
                                     params);
 
@@ -113,25 +218,100 @@ impl BitcoinRpc {
     where A:
           Into<Option<bool>>
     {
-        let rawtx = bitcoin_ser::serialize_hex(&tx).unwrap();
+        let rawtx = bitcoin_ser::serialize_hex(&tx)?;
 
         let mut params = Vec::new();
-        params.push(Json::from_serialize(rawtx).unwrap());
+        params.push(Json::from_serialize(rawtx)?);
         if let Some(allowhighfees) = allowhighfees.into() {
-            params.push(Json::from_serialize(allowhighfees).unwrap())
+            params.push(Json::from_serialize(allowhighfees)?)
         }
 
-        let response = rpc_request!(&self.client,
+        let response = rpc_request!(self,
                                     "sendrawtransaction".to_string(),
                                     params);
 
         let v: String = response.into_result()
             .map_err(|e| Error::new(e.into(), "Malformed response"))?;
-        // TODO: unwrap
-        let v = Sha256dHash::from_hex(&*v).unwrap();
+        let v = Sha256dHash::from_hex(&*v)?;
 
         Ok(v)
     }
+
+    // batch
+
+    /// Sends a batch of RPC calls in a single HTTP round trip.
+    ///
+    /// The calls accumulated in `batch` are serialized as a JSON-RPC array,
+    /// each with a distinct `id`, and the responses are matched back to the
+    /// requests by that `id`. The returned vector is in the same order the
+    /// calls were queued; each entry is an independent `RpcResult`, so a
+    /// failure in one call does not fail the whole batch.
+    pub fn send_batch(&self, batch: &BatchRequest) -> RpcResult<Vec<RpcResult<Json>>> {
+        let requests: Vec<_> = batch.calls.iter()
+            .map(|&(ref method, ref params)| {
+                self.client.build_request(method.clone(), params.clone())
+            })
+            .collect();
+
+        let responses = self.client.send_batch(&requests)
+            .map_err(|e| Error::new(e.into(), "RPC error"))?;
+
+        // `Client::send_batch` has already matched the responses to the
+        // requests by `id` and returns them positioned per input request, so
+        // here they are taken in order.
+        let results = responses.into_iter().map(|response| {
+            match response {
+                Some(response) => {
+                    if let Some(ref error) = response.error {
+                        Err(rpc_error(error))
+                    } else {
+                        match response.result {
+                            Some(result) => Ok(result),
+                            None => Err(Error::new(ErrorKind::Daemon,
+                                                   "response had neither result nor error")),
+                        }
+                    }
+                },
+                None => Err(Error::new(ErrorKind::Daemon,
+                                       "missing response for batched request")),
+            }
+        }).collect();
+
+        Ok(results)
+    }
+}
+
+/// A set of RPC calls to be issued together with [`BitcoinRpc::send_batch`].
+///
+/// [`BitcoinRpc::send_batch`]: struct.BitcoinRpc.html#method.send_batch
+#[derive(Clone, Debug, Default)]
+pub struct BatchRequest {
+    calls: Vec<(String, Vec<Json>)>,
+}
+
+impl BatchRequest {
+    /// Creates an empty batch.
+    pub fn new() -> BatchRequest {
+        BatchRequest { calls: Vec::new() }
+    }
+
+    /// Queues a raw `(method, params)` call.
+    pub fn push(&mut self, method: String, params: Vec<Json>) -> &mut BatchRequest {
+        self.calls.push((method, params));
+        self
+    }
+
+    // net
+
+    /// Queues a `getconnectioncount` call.
+    pub fn getconnectioncount(&mut self) -> &mut BatchRequest {
+        self.push("getconnectioncount".to_string(), vec![])
+    }
+
+    /// Queues a `getnetworkinfo` call.
+    pub fn getnetworkinfo(&mut self) -> &mut BatchRequest {
+        self.push("getnetworkinfo".to_string(), vec![])
+    }
 }
 
 /// The error type for bitcoin JSON-RPC operations.
@@ -159,6 +339,18 @@ impl Display for Error {
             ErrorKind::JsonRpc(ref e) => {
                 write!(fmt, "JSON-RPC error: {} ({})", self.desc, e)
             },
+            ErrorKind::Rpc { code, ref message } => {
+                write!(fmt, "RPC error {}: {}", code, message)
+            },
+            ErrorKind::Hex(ref e) => write!(fmt, "invalid hex: {}", e),
+            ErrorKind::BitcoinSerialization(ref e) => {
+                write!(fmt, "serialization error: {}", e)
+            },
+            ErrorKind::Json(ref e) => write!(fmt, "JSON error: {}", e),
+            ErrorKind::Io(ref e) => write!(fmt, "{}: {}", self.desc, e),
+            ErrorKind::InvalidCookieFile => {
+                write!(fmt, "invalid cookie file: {}", self.desc)
+            },
             ErrorKind::Daemon => write!(fmt, "bitcoind daemon error: {}", self.desc),
             ErrorKind::Other => write!(fmt, "{}", self.desc),
         }
@@ -170,14 +362,270 @@ impl Display for Error {
 pub enum ErrorKind {
     /// A JSON-RPC error.
     JsonRpc(jsonrpc::Error),
+    /// A structured error returned by the daemon in the JSON-RPC `error`
+    /// object. The `code` matches the well-known bitcoind error codes (e.g.
+    /// `-25` invalid transaction, `-5` invalid address, `-8` out of range).
+    Rpc { code: i64, message: String },
+    /// A hexadecimal string returned by the daemon could not be decoded.
+    Hex(HexError),
+    /// A value could not be serialized into, or deserialized from, the
+    /// Bitcoin wire format.
+    BitcoinSerialization(bitcoin_ser::Error),
+    /// A value could not be serialized to or from JSON.
+    Json(strason::Error),
+    /// An I/O error, e.g. while reading a cookie file.
+    Io(io::Error),
+    /// The cookie file could not be parsed into a `user:password` pair.
+    InvalidCookieFile,
     /// The daemon failed to give a valid response.
     Daemon,
     /// Any other error.
     Other,
 }
 
+/// Builds a structured [`ErrorKind::Rpc`] from a daemon `error` object.
+pub(crate) fn rpc_error(error: &jsonrpc::RpcError) -> Error {
+    Error::new(
+        ErrorKind::Rpc {
+            code: error.code as i64,
+            message: error.message.clone(),
+        },
+        "RPC error",
+    )
+}
+
+/// Splits the contents of a bitcoind `.cookie` file into its `user` and
+/// `password` halves at the first `:`.
+fn parse_cookie(contents: &str) -> RpcResult<(String, String)> {
+    let contents = contents.trim_end_matches(|c| c == '\n' || c == '\r');
+
+    let mut parts = contents.splitn(2, ':');
+    let user = parts.next().filter(|u| !u.is_empty());
+    let pass = parts.next();
+    match (user, pass) {
+        (Some(user), Some(pass)) => Ok((user.to_string(), pass.to_string())),
+        _ => Err(Error::new(ErrorKind::InvalidCookieFile, "malformed cookie file")),
+    }
+}
+
+/// Returns `true` for transport-level failures that are worth retrying, as
+/// opposed to errors the daemon deliberately returned.
+fn is_transport_error(e: &jsonrpc::Error) -> bool {
+    matches!(e, jsonrpc::Error::Hyper(_))
+}
+
 impl From<jsonrpc::Error> for ErrorKind {
     fn from(e: jsonrpc::Error) -> ErrorKind {
         ErrorKind::JsonRpc(e)
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::new(ErrorKind::Io(e), "I/O error")
+    }
+}
+
+impl From<HexError> for Error {
+    fn from(e: HexError) -> Error {
+        Error::new(ErrorKind::Hex(e), "invalid hex")
+    }
+}
+
+impl From<bitcoin_ser::Error> for Error {
+    fn from(e: bitcoin_ser::Error) -> Error {
+        Error::new(ErrorKind::BitcoinSerialization(e), "serialization error")
+    }
+}
+
+impl From<strason::Error> for Error {
+    fn from(e: strason::Error) -> Error {
+        Error::new(ErrorKind::Json(e), "JSON error")
+    }
+}
+
+/// A type alias for the boxed futures returned by the async client.
+pub type RpcFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// An asynchronous handle to a Bitcoin JSON-RPC connection.
+///
+/// This mirrors [`BitcoinRpc`] but performs the HTTP POST on an async client
+/// and returns `Future`s, so callers driving an event loop (wallets, block
+/// scanners) don't have to offload every call onto a threadpool.
+pub struct AsyncBitcoinRpc {
+    url: String,
+    auth: Option<String>,
+    client: HyperClient<hyper::client::HttpConnector, Body>,
+    rpc: Client,
+}
+
+impl AsyncBitcoinRpc {
+    /// Creates an async client to a bitcoind JSON-RPC server.
+    pub fn new(url: String, user: Option<String>, pass: Option<String>) -> Self {
+        debug_assert!(pass.is_none() || user.is_some());
+
+        let auth = user.as_ref().map(|user| {
+            let pass = pass.clone().unwrap_or_default();
+            format!("Basic {}", base64::encode(&format!("{}:{}", user, pass)))
+        });
+
+        AsyncBitcoinRpc {
+            url: url.clone(),
+            auth,
+            client: HyperClient::new(),
+            rpc: Client::new(url, user, pass),
+        }
+    }
+
+    /// Issues a single JSON-RPC call and resolves to its raw `result`.
+    ///
+    /// The request is built and serialized through the same
+    /// [`Client::build_request`] path the blocking client uses — this only
+    /// swaps the transport for an async POST. The response is parsed once the
+    /// body has been fully received, surfacing a structured [`ErrorKind::Rpc`]
+    /// when the daemon returns an error object.
+    fn request(&self, method: &str, params: Vec<Json>) -> RpcFuture<Json> {
+        let request = self.rpc.build_request(method.to_string(), params);
+        let body = match Json::from_serialize(&request) {
+            Ok(json) => json.to_bytes(),
+            Err(e) => return Box::new(future::err(Error::from(e))),
+        };
+
+        let mut builder = hyper::Request::post(&self.url);
+        builder.header(hyper::header::CONTENT_TYPE, "application/json");
+        if let Some(ref auth) = self.auth {
+            builder.header(hyper::header::AUTHORIZATION, auth.clone());
+        }
+
+        let request = match builder.body(Body::from(body)) {
+            Ok(request) => request,
+            Err(_) => {
+                return Box::new(future::err(
+                    Error::new(ErrorKind::Other, "malformed request")));
+            },
+        };
+
+        let future = self.client.request(request)
+            .and_then(|response| response.into_body().concat2())
+            .map_err(|e| Error::new(ErrorKind::Other, format!("transport error: {}", e)))
+            .and_then(|chunk| decode_response(chunk.as_ref()));
+
+        Box::new(future)
+    }
+
+    /// Resolves to the daemon's network information.
+    pub fn getnetworkinfo(&self) -> RpcFuture<net::NetworkInfo> {
+        Box::new(self.request("getnetworkinfo", vec![]).and_then(|json| {
+            json.into_deserialize()
+                .map_err(|e| Error::new(ErrorKind::Json(e), "Malformed response"))
+        }))
+    }
+}
+
+/// A source of blocks and headers that chain-following components can poll
+/// for the tip and fetch blocks from concurrently.
+pub trait BlockSource {
+    /// Fetches the full block identified by `hash`.
+    fn get_block(&self, hash: &Sha256dHash) -> RpcFuture<Block>;
+
+    /// Returns the hash of the current best block, together with its height
+    /// when the source can provide it.
+    fn get_best_block(&self) -> RpcFuture<(Sha256dHash, Option<u32>)>;
+
+    /// Fetches the header of the block identified by `hash`.
+    fn get_header(&self, hash: &Sha256dHash) -> RpcFuture<BlockHeader>;
+}
+
+impl BlockSource for AsyncBitcoinRpc {
+    fn get_block(&self, hash: &Sha256dHash) -> RpcFuture<Block> {
+        let params = match (Json::from_serialize(hash.be_hex_string()),
+                            Json::from_serialize(0)) {
+            (Ok(hash), Ok(verbosity)) => vec![hash, verbosity],
+            _ => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "could not encode parameters"))),
+        };
+
+        Box::new(self.request("getblock", params).and_then(|json| {
+            let raw: String = json.into_deserialize()
+                .map_err(|e| Error::new(ErrorKind::Json(e), "Malformed response"))?;
+            let bytes = hex_bytes(&raw)?;
+            bitcoin_ser::deserialize(&bytes).map_err(Error::from)
+        }))
+    }
+
+    fn get_best_block(&self) -> RpcFuture<(Sha256dHash, Option<u32>)> {
+        Box::new(self.request("getbestblockhash", vec![]).and_then(|json| {
+            let hash: String = json.into_deserialize()
+                .map_err(|e| Error::new(ErrorKind::Json(e), "Malformed response"))?;
+            let hash = Sha256dHash::from_hex(&hash)?;
+            Ok((hash, None))
+        }))
+    }
+
+    fn get_header(&self, hash: &Sha256dHash) -> RpcFuture<BlockHeader> {
+        let params = match (Json::from_serialize(hash.be_hex_string()),
+                            Json::from_serialize(false)) {
+            (Ok(hash), Ok(verbose)) => vec![hash, verbose],
+            _ => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "could not encode parameters"))),
+        };
+
+        Box::new(self.request("getblockheader", params).and_then(|json| {
+            let raw: String = json.into_deserialize()
+                .map_err(|e| Error::new(ErrorKind::Json(e), "Malformed response"))?;
+            let bytes = hex_bytes(&raw)?;
+            bitcoin_ser::deserialize(&bytes).map_err(Error::from)
+        }))
+    }
+}
+
+/// Parses a JSON-RPC response body through the same [`jsonrpc::Response`]
+/// decoding the blocking client relies on, surfacing a daemon `error` object
+/// via the shared [`rpc_error`] mapping.
+fn decode_response(bytes: &[u8]) -> RpcResult<Json> {
+    let json = Json::from_reader(bytes)
+        .map_err(|e| Error::new(ErrorKind::Json(e), "Malformed response"))?;
+    let response: jsonrpc::Response = json.into_deserialize()
+        .map_err(|e| Error::new(ErrorKind::Json(e), "Malformed response"))?;
+
+    if let Some(ref error) = response.error {
+        return Err(rpc_error(error));
+    }
+
+    response.result.ok_or_else(|| {
+        Error::new(ErrorKind::Daemon, "response had neither result nor error")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rpc_error_display() {
+        let e = Error::new(
+            ErrorKind::Rpc { code: -25, message: "bad transaction".to_string() },
+            "RPC error");
+        assert_eq!(e.to_string(), "RPC error -25: bad transaction");
+    }
+
+    #[test]
+    fn parse_cookie_splits_on_first_colon() {
+        let (user, pass) = parse_cookie("__cookie__:deadbeef\n").unwrap();
+        assert_eq!(user, "__cookie__");
+        // The password may itself contain colons.
+        let (_, pass2) = parse_cookie("__cookie__:dead:beef").unwrap();
+        assert_eq!(pass, "deadbeef");
+        assert_eq!(pass2, "dead:beef");
+    }
+
+    #[test]
+    fn parse_cookie_rejects_malformed() {
+        for contents in &["nocolon", ":nopassword"] {
+            match parse_cookie(contents) {
+                Err(Error { kind: ErrorKind::InvalidCookieFile, .. }) => {},
+                other => panic!("expected InvalidCookieFile, got {:?}", other),
+            }
+        }
+    }
+}